@@ -8,13 +8,13 @@ use std::{
 };
 
 use crate::{
-    connection::{self, ConnectionInner, SharedStateRef},
+    connection::{self, ConnectionInner, Priority, SharedStateRef},
     error::{Code, Error},
     frame::FrameStream,
     proto::{frame::Frame, headers::Header, varint::VarInt},
     qpack, quic, stream,
 };
-use tracing::{trace, warn};
+use tracing::trace;
 
 pub fn builder<C: quic::Connection<Bytes>>() -> Builder<C> {
     Builder::new()
@@ -32,6 +32,10 @@ pub struct SendRequest<T: quic::OpenStreams<Bytes>> {
     open: T,
     conn_state: SharedStateRef,
     max_field_section_size: u64, // maximum size for a header we receive
+    next_request_stream_id: VarInt,
+    // whether `Builder::enable_datagram` was set; gates `with_datagrams` registration so we
+    // don't leak a `datagram_senders` entry per request on connections that never enabled them
+    datagram_enabled: bool,
 }
 
 impl<T> SendRequest<T>
@@ -42,18 +46,32 @@ where
         &mut self,
         req: http::Request<()>,
     ) -> Result<RequestStream<FrameStream<T::BidiStream>>, Error> {
-        let peer_max_field_section_size = {
+        let (peer_max_field_section_size, goaway_received, lenient_connection_headers) = {
             let state = self.conn_state.0.read().expect("send request lock state");
-            state.peer_max_field_section_size
+            (
+                state.peer_max_field_section_size,
+                state.goaway_received,
+                state.lenient_connection_headers,
+            )
         };
 
+        let stream_id = self.next_request_stream_id;
+        if let Some(max_id) = goaway_received {
+            if stream_id.0 >= max_id.0 {
+                return Err(Code::H3_REQUEST_REJECTED
+                    .with_reason("connection is going away, refusing new request"));
+            }
+        }
+        self.next_request_stream_id = VarInt::from_u32(stream_id.0 as u32 + 4);
+
         let (parts, _) = req.into_parts();
         let request::Parts {
             method,
             uri,
-            headers,
+            mut headers,
             ..
         } = parts;
+        connection::sanitize_connection_headers(&mut headers, lenient_connection_headers)?;
         let headers = Header::request(method, uri, headers)?;
 
         let mut stream =
@@ -67,12 +85,101 @@ where
 
         stream::write(&mut stream, Frame::Headers(block.freeze())).await?;
 
+        let mut inner = connection::RequestStream::new(
+            FrameStream::new(stream),
+            self.max_field_section_size,
+            self.conn_state.clone(),
+        );
+        if self.datagram_enabled {
+            inner = inner.with_datagrams(stream_id);
+        }
+
+        Ok(RequestStream {
+            inner: inner.register_close_notifier(),
+            stream_id,
+        })
+    }
+
+    /// Same as [`send_request`](Self::send_request), but attaches a `priority`
+    /// header field carrying `priority` as defined in
+    /// [RFC9218](https://www.rfc-editor.org/rfc/rfc9218.html).
+    pub async fn send_request_with_priority(
+        &mut self,
+        mut req: http::Request<()>,
+        priority: Priority,
+    ) -> Result<RequestStream<FrameStream<T::BidiStream>>, Error> {
+        req.headers_mut().insert(
+            http::header::HeaderName::from_static("priority"),
+            http::header::HeaderValue::from_str(&priority.serialize())
+                .map_err(|e| Code::H3_INTERNAL_ERROR.with_cause(e))?,
+        );
+        self.send_request(req).await
+    }
+
+    /// Send an extended CONNECT request ([RFC9220](https://www.rfc-editor.org/rfc/rfc9220.html)),
+    /// establishing `uri` as a bidirectional tunnel for `protocol` (e.g.
+    /// `"websocket"` or `"webtransport"`). The returned `RequestStream` can
+    /// be used with `send_data`/`recv_data` as a raw byte tunnel.
+    ///
+    /// Errors with `H3_SETTINGS_ERROR` unless the peer has advertised
+    /// `SETTINGS_ENABLE_CONNECT_PROTOCOL`.
+    pub async fn send_extended_connect(
+        &mut self,
+        uri: http::Uri,
+        protocol: &str,
+    ) -> Result<RequestStream<FrameStream<T::BidiStream>>, Error> {
+        let (peer_max_field_section_size, goaway_received, peer_enable_connect_protocol) = {
+            let state = self
+                .conn_state
+                .0
+                .read()
+                .expect("send extended connect lock state");
+            (
+                state.peer_max_field_section_size,
+                state.goaway_received,
+                state.peer_enable_connect_protocol,
+            )
+        };
+
+        if !peer_enable_connect_protocol {
+            return Err(Code::H3_SETTINGS_ERROR
+                .with_reason("peer did not advertise SETTINGS_ENABLE_CONNECT_PROTOCOL"));
+        }
+
+        let stream_id = self.next_request_stream_id;
+        if let Some(max_id) = goaway_received {
+            if stream_id.0 >= max_id.0 {
+                return Err(Code::H3_REQUEST_REJECTED
+                    .with_reason("connection is going away, refusing new request"));
+            }
+        }
+        self.next_request_stream_id = VarInt::from_u32(stream_id.0 as u32 + 4);
+
+        let headers = Header::extended_connect(uri, protocol)?;
+
+        let mut stream =
+            future::poll_fn(|cx| self.open.poll_open_bidi(cx).map_err(Error::transport)).await?;
+
+        let mut block = BytesMut::new();
+        let mem_size = qpack::encode_stateless(&mut block, headers)?;
+        if mem_size > peer_max_field_section_size {
+            return Err(Error::header_too_big(mem_size, peer_max_field_section_size));
+        }
+
+        stream::write(&mut stream, Frame::Headers(block.freeze())).await?;
+
+        let mut inner = connection::RequestStream::new(
+            FrameStream::new(stream),
+            self.max_field_section_size,
+            self.conn_state.clone(),
+        );
+        if self.datagram_enabled {
+            inner = inner.with_datagrams(stream_id);
+        }
+
         Ok(RequestStream {
-            inner: connection::RequestStream::new(
-                FrameStream::new(stream),
-                self.max_field_section_size,
-                self.conn_state.clone(),
-            ),
+            inner: inner.register_close_notifier(),
+            stream_id,
         })
     }
 
@@ -97,8 +204,17 @@ where
         while let Poll::Ready(frame) = self.inner.poll_control(cx)? {
             match frame {
                 Frame::Settings(_) => trace!("Got settings"),
-                f @ Frame::Goaway(_) => {
-                    warn!("Control frame ignored {:?}", f);
+                Frame::Goaway(id) => trace!("Received GOAWAY id={:?}", id),
+                Frame::PriorityUpdateRequest { element_id, .. } => {
+                    trace!("Received PRIORITY_UPDATE for request {:?}", element_id)
+                }
+                Frame::PriorityUpdatePush { element_id, .. } => {
+                    trace!("Received PRIORITY_UPDATE for push {:?}", element_id)
+                }
+                Frame::CancelPush(push_id) => trace!("Push {:?} cancelled by server", push_id),
+                Frame::MaxPushId(_) => {
+                    return Poll::Ready(Err(Code::H3_FRAME_UNEXPECTED
+                        .with_reason("MAX_PUSH_ID is sent by the client, not received")))
                 }
                 frame => {
                     return Poll::Ready(Err(Code::H3_FRAME_UNEXPECTED
@@ -107,6 +223,10 @@ where
             }
         }
 
+        if let Poll::Ready(Err(e)) = self.inner.poll_datagrams(cx) {
+            return Poll::Ready(Err(e));
+        }
+
         if let Poll::Ready(_) = self.inner.poll_accept_request(cx) {
             return Poll::Ready(Err(self.inner.close(
                 Code::H3_STREAM_CREATION_ERROR,
@@ -116,6 +236,86 @@ where
 
         Poll::Pending
     }
+
+    /// Poll for the push stream promised as `push_id`, once the server has
+    /// opened it.
+    pub fn poll_accept_push(
+        &mut self,
+        push_id: VarInt,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<RequestStream<FrameStream<C::RecvStream>>>, Error>> {
+        match self.inner.poll_accept_push(push_id, cx) {
+            Poll::Ready(Ok(Some(stream))) => {
+                let max_field_section_size = self
+                    .inner
+                    .shared
+                    .read("poll_accept_push")
+                    .peer_max_field_section_size;
+                Poll::Ready(Ok(Some(RequestStream {
+                    inner: connection::RequestStream::new(
+                        stream,
+                        max_field_section_size,
+                        self.inner.shared.clone(),
+                    ),
+                    // pushed responses are receive-only and have no datagram flow of their own
+                    stream_id: push_id,
+                })))
+            }
+            Poll::Ready(Ok(None)) => Poll::Ready(Ok(None)),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    /// Raise the number of pushes the server may promise on this connection
+    /// by sending a `MAX_PUSH_ID` frame.
+    pub async fn set_max_push_id(&mut self, max_push_id: u64) -> Result<(), Error> {
+        let max_push_id =
+            VarInt::from_u64(max_push_id).map_err(|e| Code::H3_ID_ERROR.with_cause(e))?;
+        self.inner.send_max_push_id(max_push_id).await
+    }
+
+    /// Abort the push identified by `push_id`, by sending a `CANCEL_PUSH`
+    /// frame.
+    pub async fn cancel_push(&mut self, push_id: u64) -> Result<(), Error> {
+        let push_id = VarInt::from_u64(push_id).map_err(|e| Code::H3_ID_ERROR.with_cause(e))?;
+        self.inner.send_cancel_push(push_id).await
+    }
+
+    /// Ask the server to reprioritize the push identified by `push_id`, by
+    /// sending a `PRIORITY_UPDATE` frame carrying `priority`.
+    pub async fn update_push_priority(
+        &mut self,
+        push_id: u64,
+        priority: Priority,
+    ) -> Result<(), Error> {
+        let push_id = VarInt::from_u64(push_id).map_err(|e| Code::H3_ID_ERROR.with_cause(e))?;
+        self.inner.send_push_priority_update(push_id, priority).await
+    }
+
+    /// The priority most recently signalled by the server for the request
+    /// stream or push identified by `id`, defaulting to [`Priority::default`]
+    /// if it never sent one.
+    pub fn priority_for(&self, id: VarInt) -> Priority {
+        self.inner.priority_for(id)
+    }
+
+    /// Initiate a graceful shutdown of the connection, as described in
+    /// [RFC9114 section 5.2](https://www.rfc-editor.org/rfc/rfc9114.html#section-5.2).
+    ///
+    /// As the client, the GOAWAY frame we send carries `max_push_id`, the
+    /// largest push id we will still accept — a server-sent GOAWAY is the one
+    /// that carries a request stream id bound, since only the client
+    /// initiates request streams. All requests already in flight are allowed
+    /// to complete (regardless of `max_push_id`) before the connection is
+    /// finally closed.
+    pub async fn shutdown(&mut self, max_push_id: u64) -> Result<(), Error> {
+        let id = VarInt::from_u64(max_push_id).map_err(|e| Code::H3_ID_ERROR.with_cause(e))?;
+        self.inner.shutdown(id).await?;
+        self.inner
+            .close(Code::H3_NO_ERROR, "connection shutting down gracefully");
+        Ok(())
+    }
 }
 
 pub struct Builder<C>
@@ -123,6 +323,9 @@ where
     C: quic::Connection<Bytes>,
 {
     pub(super) max_field_section_size: u64,
+    lenient_connection_headers: bool,
+    enable_connect_protocol: bool,
+    enable_datagram: bool,
     _conn: PhantomData<C>,
 }
 
@@ -134,6 +337,9 @@ where
     pub(super) fn new() -> Self {
         Builder {
             max_field_section_size: VarInt::MAX.0,
+            lenient_connection_headers: false,
+            enable_connect_protocol: false,
+            enable_datagram: false,
             _conn: PhantomData,
         }
     }
@@ -143,19 +349,59 @@ where
         self
     }
 
+    /// When `true`, connection-specific header fields forbidden by
+    /// [RFC9114 section 4.2](https://www.rfc-editor.org/rfc/rfc9114.html#section-4.2)
+    /// (`Connection`, `Keep-Alive`, `Proxy-Connection`, `Transfer-Encoding`,
+    /// `Upgrade`, and a `TE` carrying anything but `trailers`) are silently
+    /// stripped instead of aborting the request. Useful for a proxy
+    /// forwarding downgraded HTTP/1 requests. Defaults to `false` (strict
+    /// rejection).
+    pub fn lenient_connection_headers(&mut self, value: bool) -> &mut Self {
+        self.lenient_connection_headers = value;
+        self
+    }
+
+    /// Advertise `SETTINGS_ENABLE_CONNECT_PROTOCOL` ([RFC9220](https://www.rfc-editor.org/rfc/rfc9220.html)),
+    /// allowing [`SendRequest::send_extended_connect`] to be used for
+    /// tunneling protocols such as WebTransport once the peer has
+    /// reciprocated with the same setting.
+    pub fn enable_connect_protocol(&mut self, value: bool) -> &mut Self {
+        self.enable_connect_protocol = value;
+        self
+    }
+
+    /// Advertise `SETTINGS_H3_DATAGRAM` ([RFC9297](https://www.rfc-editor.org/rfc/rfc9297.html)),
+    /// allowing `RequestStream::send_datagram`/`poll_recv_datagram` to be
+    /// used once the peer has reciprocated with the same setting.
+    pub fn enable_datagram(&mut self, value: bool) -> &mut Self {
+        self.enable_datagram = value;
+        self
+    }
+
     pub async fn build(&mut self, quic: C) -> Result<(Connection<C>, SendRequest<O>), Error> {
         let open = quic.opener();
         let conn_state = SharedStateRef::default();
+        conn_state
+            .write("build lenient_connection_headers")
+            .lenient_connection_headers = self.lenient_connection_headers;
 
         Ok((
             Connection {
-                inner: ConnectionInner::new(quic, self.max_field_section_size, conn_state.clone())
-                    .await?,
+                inner: ConnectionInner::new(
+                    quic,
+                    self.max_field_section_size,
+                    conn_state.clone(),
+                    self.enable_connect_protocol,
+                    self.enable_datagram,
+                )
+                .await?,
             },
             SendRequest {
                 open,
                 conn_state,
                 max_field_section_size: self.max_field_section_size,
+                next_request_stream_id: VarInt::from_u32(0),
+                datagram_enabled: self.enable_datagram,
             },
         ))
     }
@@ -163,6 +409,20 @@ where
 
 pub struct RequestStream<S> {
     inner: connection::RequestStream<S, Bytes>,
+    stream_id: VarInt,
+}
+
+impl<S> RequestStream<S> {
+    /// Send an HTTP/3 datagram associated with this request stream, per
+    /// [RFC9297](https://www.rfc-editor.org/rfc/rfc9297.html).
+    pub fn send_datagram(&mut self, data: Bytes) -> Result<(), Error> {
+        self.inner.send_datagram(self.stream_id, data)
+    }
+
+    /// Poll for the next HTTP/3 datagram addressed to this request stream.
+    pub fn poll_recv_datagram(&mut self, cx: &mut Context<'_>) -> Poll<Option<Bytes>> {
+        self.inner.poll_recv_datagram(cx)
+    }
 }
 
 impl<S> RequestStream<FrameStream<S>>
@@ -192,7 +452,20 @@ where
             ));
         }
 
-        let (status, headers) = Header::try_from(fields)?.into_response_parts()?;
+        let (status, mut headers) = Header::try_from(fields)?.into_response_parts()?;
+
+        let lenient_connection_headers = self
+            .inner
+            .conn_state
+            .read("recv_response lenient_connection_headers")
+            .lenient_connection_headers;
+        if let Err(e) =
+            connection::sanitize_connection_headers(&mut headers, lenient_connection_headers)
+        {
+            self.inner.stream.stop_sending(Code::H3_MESSAGE_ERROR);
+            return Err(e);
+        }
+
         let mut resp = Response::new(());
         *resp.status_mut() = status;
         *resp.headers_mut() = headers;
@@ -232,6 +505,19 @@ where
         self.inner.send_trailers(trailers).await
     }
 
+    /// Same as [`send_trailers`](Self::send_trailers), but attaches a
+    /// `priority` header field carrying `priority`, as defined in
+    /// [RFC9218](https://www.rfc-editor.org/rfc/rfc9218.html).
+    pub async fn send_trailers_with_priority(
+        &mut self,
+        trailers: HeaderMap,
+        priority: Priority,
+    ) -> Result<(), Error> {
+        self.inner
+            .send_trailers_with_priority(trailers, priority)
+            .await
+    }
+
     pub async fn finish(&mut self) -> Result<(), Error> {
         self.inner.finish().await
     }
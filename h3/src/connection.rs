@@ -1,4 +1,5 @@
 use std::{
+    collections::HashMap,
     convert::TryFrom,
     marker::PhantomData,
     sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
@@ -6,8 +7,12 @@ use std::{
 };
 
 use bytes::{Bytes, BytesMut};
-use futures::{channel::oneshot, future, ready};
-use http::HeaderMap;
+use futures::{
+    channel::{mpsc, oneshot},
+    future, ready,
+    stream::StreamExt,
+};
+use http::{HeaderMap, HeaderName};
 
 use crate::{
     error::{Code, Error},
@@ -21,12 +26,182 @@ use crate::{
     stream::{AcceptRecvStream, AcceptedRecvStream},
 };
 
+/// A request's priority, as defined by
+/// [RFC9218](https://www.rfc-editor.org/rfc/rfc9218.html).
+///
+/// Carried either as a `priority` header field or in a `PRIORITY_UPDATE`
+/// frame, serialized as a Structured-Fields dictionary with an urgency
+/// member `u` (0-7, default 3, lower is more urgent) and an incremental
+/// member `i` (boolean, default `false`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Priority {
+    urgency: u8,
+    incremental: bool,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Self {
+            urgency: 3,
+            incremental: false,
+        }
+    }
+}
+
+impl Priority {
+    /// Build a new `Priority`, rejecting urgency values outside `0..=7`.
+    pub fn new(urgency: u8, incremental: bool) -> Result<Self, Error> {
+        if urgency > 7 {
+            return Err(Code::H3_GENERAL_PROTOCOL_ERROR
+                .with_reason(format!("priority urgency {} out of range 0-7", urgency)));
+        }
+        Ok(Self {
+            urgency,
+            incremental,
+        })
+    }
+
+    pub fn urgency(&self) -> u8 {
+        self.urgency
+    }
+
+    pub fn incremental(&self) -> bool {
+        self.incremental
+    }
+
+    /// Serialize as an ASCII structured-field dictionary value, e.g. `u=5, i`.
+    pub fn serialize(&self) -> String {
+        if self.incremental {
+            format!("u={}, i", self.urgency)
+        } else {
+            format!("u={}", self.urgency)
+        }
+    }
+
+    /// Parse a structured-field dictionary value as sent in a `priority`
+    /// header field or a `PRIORITY_UPDATE` frame payload. Unknown members
+    /// are ignored; missing members fall back to their defaults.
+    pub fn parse(value: &str) -> Result<Self, Error> {
+        let mut urgency = 3u8;
+        let mut incremental = false;
+
+        for member in value.split(',') {
+            let member = member.trim();
+            if member.is_empty() {
+                continue;
+            }
+            let mut parts = member.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim();
+            let val = parts.next().map(str::trim);
+
+            match key {
+                "u" => {
+                    urgency = val
+                        .and_then(|v| v.parse::<u8>().ok())
+                        .ok_or_else(|| Code::H3_GENERAL_PROTOCOL_ERROR.with_reason("invalid priority urgency"))?;
+                }
+                "i" => {
+                    incremental = match val {
+                        None | Some("?1") => true,
+                        Some("?0") => false,
+                        Some(_) => {
+                            return Err(Code::H3_GENERAL_PROTOCOL_ERROR
+                                .with_reason("invalid priority incremental value"))
+                        }
+                    };
+                }
+                _ => (),
+            }
+        }
+
+        Priority::new(urgency, incremental)
+    }
+}
+
 #[doc(hidden)]
 pub struct SharedState {
     // maximum size for a header we send
     pub peer_max_field_section_size: u64,
     // connection-wide error, concerns all RequestStreams and drivers
     pub error: Option<Error>,
+    // id of the peer's GOAWAY, if one has been received
+    pub goaway_received: Option<VarInt>,
+    // priorities received via PRIORITY_UPDATE frames, keyed by request/push id
+    pub priorities: HashMap<VarInt, Priority>,
+    // push ids aborted via CANCEL_PUSH, by either endpoint
+    pub cancelled_pushes: std::collections::HashSet<VarInt>,
+    // if true, connection-specific header fields (RFC9114 section 4.2) are silently
+    // stripped instead of causing the HEADERS to be rejected
+    pub lenient_connection_headers: bool,
+    // whether the peer advertised SETTINGS_ENABLE_CONNECT_PROTOCOL (RFC9220)
+    pub peer_enable_connect_protocol: bool,
+    // whether the peer advertised SETTINGS_H3_DATAGRAM (RFC9297)
+    pub peer_datagram_enabled: bool,
+    // inbound HTTP/3 datagram demux: quarter stream id -> the owning RequestStream's channel
+    pub datagram_senders: HashMap<VarInt, mpsc::UnboundedSender<Bytes>>,
+    // outbound HTTP/3 datagrams queued by RequestStreams, drained by the connection driver
+    // and turned into real QUIC datagrams; `None` until datagrams are enabled locally
+    pub datagram_outbound: Option<mpsc::UnboundedSender<(VarInt, Bytes)>>,
+    // each `RequestStream` that calls `register_close_notifier` registers the receiving half
+    // of a fresh oneshot channel here, and fires the sending half on drop; `shutdown`
+    // collects these to learn when in-flight requests have actually finished
+    pub request_close_tx: Option<mpsc::UnboundedSender<oneshot::Receiver<()>>>,
+}
+
+/// Hop-by-hop header fields forbidden in HTTP/3 by
+/// [RFC9114 section 4.2](https://www.rfc-editor.org/rfc/rfc9114.html#section-4.2).
+fn connection_specific_headers() -> [HeaderName; 5] {
+    [
+        http::header::CONNECTION,
+        http::header::TRANSFER_ENCODING,
+        http::header::UPGRADE,
+        HeaderName::from_static("keep-alive"),
+        HeaderName::from_static("proxy-connection"),
+    ]
+}
+
+/// The quarter stream id (RFC9297's datagram flow identifier) for the
+/// client-initiated bidirectional stream `stream_id`. `stream_id` is always
+/// a valid `VarInt`, so dividing it can't overflow one.
+fn quarter_stream_id(stream_id: VarInt) -> VarInt {
+    VarInt::from_u64(stream_id.0 / 4).expect("a quarter of a valid VarInt fits in one")
+}
+
+/// Validate (or strip) connection-specific header fields from `headers`,
+/// per RFC9114 section 4.2. In strict mode, presence of any forbidden field,
+/// or a `TE` field carrying anything but `trailers`, is an error. In lenient
+/// mode the offending fields are silently removed so that downgraded HTTP/1
+/// requests can be forwarded by a proxy without aborting the stream.
+pub(crate) fn sanitize_connection_headers(
+    headers: &mut HeaderMap,
+    lenient: bool,
+) -> Result<(), Error> {
+    for name in connection_specific_headers() {
+        if headers.contains_key(&name) {
+            if lenient {
+                headers.remove(&name);
+            } else {
+                return Err(Code::H3_MESSAGE_ERROR
+                    .with_reason(format!("forbidden connection-specific header field: {}", name)));
+            }
+        }
+    }
+
+    let te_conforms = headers
+        .get_all(http::header::TE)
+        .iter()
+        .all(|v| v.as_bytes() == b"trailers");
+    if !te_conforms {
+        if lenient {
+            headers.remove(http::header::TE);
+        } else {
+            return Err(
+                Code::H3_MESSAGE_ERROR.with_reason("TE header field must only contain trailers")
+            );
+        }
+    }
+
+    Ok(())
 }
 
 #[derive(Clone)]
@@ -48,6 +223,15 @@ impl Default for SharedStateRef {
         Self(Arc::new(RwLock::new(SharedState {
             peer_max_field_section_size: VarInt::MAX.0,
             error: None,
+            goaway_received: None,
+            priorities: HashMap::new(),
+            cancelled_pushes: std::collections::HashSet::new(),
+            lenient_connection_headers: false,
+            peer_enable_connect_protocol: false,
+            peer_datagram_enabled: false,
+            datagram_senders: HashMap::new(),
+            datagram_outbound: None,
+            request_close_tx: None,
         })))
     }
 }
@@ -76,7 +260,15 @@ where
     control_recv: Option<FrameStream<C::RecvStream>>,
     pending_recv_streams: Vec<AcceptRecvStream<C::RecvStream>>,
     got_peer_settings: bool,
-    request_close_receivers: Vec<oneshot::Receiver<()>>,
+    // receiving half of the channel `RequestStream`s register their close notifier on; see
+    // `SharedState::request_close_tx`
+    request_close_rx: mpsc::UnboundedReceiver<oneshot::Receiver<()>>,
+    // push streams accepted from the transport, waiting to be claimed via `poll_accept_push`
+    pending_push_streams: Vec<(VarInt, FrameStream<C::RecvStream>)>,
+    // budget we have granted the peer via a sent MAX_PUSH_ID frame
+    max_push_id_sent: VarInt,
+    // `Some` once datagrams are enabled locally; receives payloads queued by RequestStreams
+    datagram_outbound: Option<mpsc::UnboundedReceiver<(VarInt, Bytes)>>,
 }
 
 impl<C> ConnectionInner<C>
@@ -87,6 +279,8 @@ where
         mut conn: C,
         max_field_section_size: u64,
         shared: SharedStateRef,
+        enable_connect_protocol: bool,
+        enable_datagram: bool,
     ) -> Result<Self, Error> {
         let mut control_send = future::poll_fn(|mut cx| conn.poll_open_send(&mut cx))
             .await
@@ -96,10 +290,28 @@ where
         settings
             .insert(SettingId::MAX_HEADER_LIST_SIZE, max_field_section_size)
             .map_err(|e| Code::H3_INTERNAL_ERROR.with_cause(e))?;
+        if enable_connect_protocol {
+            settings
+                .insert(SettingId::ENABLE_CONNECT_PROTOCOL, 1)
+                .map_err(|e| Code::H3_INTERNAL_ERROR.with_cause(e))?;
+        }
+        let datagram_outbound = if enable_datagram {
+            settings
+                .insert(SettingId::H3_DATAGRAM, 1)
+                .map_err(|e| Code::H3_INTERNAL_ERROR.with_cause(e))?;
+            let (tx, rx) = mpsc::unbounded();
+            shared.write("new datagram_outbound").datagram_outbound = Some(tx);
+            Some(rx)
+        } else {
+            None
+        };
 
         stream::write(&mut control_send, StreamType::CONTROL).await?;
         stream::write(&mut control_send, Frame::Settings(settings)).await?;
 
+        let (request_close_tx, request_close_rx) = mpsc::unbounded();
+        shared.write("new request_close_tx").request_close_tx = Some(request_close_tx);
+
         Ok(Self {
             shared,
             conn,
@@ -109,10 +321,53 @@ where
             control_recv: None,
             pending_recv_streams: Vec::with_capacity(3),
             got_peer_settings: false,
-            request_close_receivers: Vec::new(),
+            request_close_rx,
+            pending_push_streams: Vec::new(),
+            max_push_id_sent: VarInt::from_u32(0),
+            datagram_outbound,
         })
     }
 
+    /// Drain datagrams queued by `RequestStream::send_datagram` out onto the
+    /// QUIC connection, and demultiplex inbound QUIC datagrams back to the
+    /// `RequestStream` owning their quarter stream id, per
+    /// [RFC9297](https://www.rfc-editor.org/rfc/rfc9297.html).
+    pub fn poll_datagrams(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        if let Some(outbound) = self.datagram_outbound.as_mut() {
+            while let Poll::Ready(Some((quarter_id, payload))) = outbound.poll_next_unpin(cx) {
+                let mut buf = BytesMut::with_capacity(payload.len() + 8);
+                quarter_id.encode(&mut buf);
+                buf.extend_from_slice(&payload);
+                self.conn
+                    .send_datagram(buf.freeze())
+                    .map_err(Error::transport)?;
+            }
+        }
+
+        loop {
+            match self.conn.poll_accept_datagram(cx).map_err(Error::transport)? {
+                Poll::Ready(Some(mut datagram)) => {
+                    let quarter_id = match VarInt::decode(&mut datagram) {
+                        Ok(id) => id,
+                        Err(_) => continue,
+                    };
+                    if let Some(sender) = self
+                        .shared
+                        .read("poll_datagrams demux")
+                        .datagram_senders
+                        .get(&quarter_id)
+                    {
+                        let _ = sender.unbounded_send(datagram);
+                    }
+                }
+                Poll::Ready(None) => break,
+                Poll::Pending => break,
+            }
+        }
+
+        Poll::Pending
+    }
+
     pub fn poll_accept_request(
         &mut self,
         cx: &mut Context<'_>,
@@ -147,6 +402,21 @@ where
                 AcceptedRecvStream::Control(s) => {
                     self.control_recv = Some(s);
                 }
+                AcceptedRecvStream::Push(push_id, s) => {
+                    let cancelled = self
+                        .shared
+                        .read("poll_accept_recv push cancelled")
+                        .cancelled_pushes
+                        .contains(&push_id);
+                    if cancelled {
+                        // peer is pushing a cancelled push id, ignore it
+                    } else if push_id.0 > self.max_push_id_sent.0 {
+                        let mut s = s;
+                        s.stop_sending(Code::H3_ID_ERROR);
+                    } else {
+                        self.pending_push_streams.push((push_id, s));
+                    }
+                }
                 _ => (),
             }
         }
@@ -154,6 +424,69 @@ where
         Poll::Pending
     }
 
+    /// Poll for a push stream accepted for `push_id`, previously announced
+    /// by the peer via a PUSH_PROMISE on its associated request stream.
+    pub fn poll_accept_push(
+        &mut self,
+        push_id: VarInt,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<FrameStream<C::RecvStream>>, Error>> {
+        if let Poll::Ready(Err(e)) = self.poll_accept_recv(cx) {
+            return Poll::Ready(Err(e));
+        }
+
+        if let Some(index) = self
+            .pending_push_streams
+            .iter()
+            .position(|(id, _)| *id == push_id)
+        {
+            let (_, stream) = self.pending_push_streams.remove(index);
+            return Poll::Ready(Ok(Some(stream)));
+        }
+
+        Poll::Pending
+    }
+
+    /// Raise the budget of push ids the peer is allowed to use, by sending
+    /// a `MAX_PUSH_ID` frame on the control stream.
+    pub async fn send_max_push_id(&mut self, max_push_id: VarInt) -> Result<(), Error> {
+        if max_push_id.0 > self.max_push_id_sent.0 {
+            self.max_push_id_sent = max_push_id;
+        }
+        stream::write(&mut self.control_send, Frame::MaxPushId(max_push_id)).await
+    }
+
+    /// Abort the push identified by `push_id` by sending a `CANCEL_PUSH`
+    /// frame on the control stream.
+    pub async fn send_cancel_push(&mut self, push_id: VarInt) -> Result<(), Error> {
+        let mut shared = self.shared.write("send_cancel_push");
+        shared.cancelled_pushes.insert(push_id);
+        drop(shared);
+        self.pending_push_streams.retain(|(id, _)| *id != push_id);
+        stream::write(&mut self.control_send, Frame::CancelPush(push_id)).await
+    }
+
+    /// Open a new unidirectional push stream for `push_id`, writing the
+    /// `StreamType::PUSH` stream header and the push id, per
+    /// [RFC9114 section 4.4](https://www.rfc-editor.org/rfc/rfc9114.html#section-4.4).
+    /// The caller writes the pushed response on the returned `RequestStream`
+    /// exactly like an ordinary response (`send_data`, `send_trailers`, ...).
+    pub async fn open_push_stream(
+        &mut self,
+        push_id: VarInt,
+    ) -> Result<RequestStream<C::SendStream, Bytes>, Error> {
+        let mut send = future::poll_fn(|mut cx| self.conn.poll_open_send(&mut cx))
+            .await
+            .map_err(|e| Code::H3_STREAM_CREATION_ERROR.with_cause(e))?;
+        stream::write(&mut send, StreamType::PUSH).await?;
+        stream::write(&mut send, push_id).await?;
+        Ok(RequestStream::new(
+            send,
+            self.peer_max_field_section_size,
+            self.shared.clone(),
+        ))
+    }
+
     pub fn poll_control(&mut self, cx: &mut Context<'_>) -> Poll<Result<Frame, Error>> {
         while self.control_recv.is_none() {
             ready!(self.poll_accept_recv(cx))?;
@@ -170,18 +503,74 @@ where
             Some(frame) => match frame {
                 Frame::Settings(settings) if !self.got_peer_settings => {
                     self.got_peer_settings = true;
-                    self.shared
-                        .write("connection settings write")
-                        .peer_max_field_section_size = settings
+                    let mut shared = self.shared.write("connection settings write");
+                    shared.peer_max_field_section_size = settings
                         .get(SettingId::MAX_HEADER_LIST_SIZE)
                         .unwrap_or(VarInt::MAX.0);
+                    shared.peer_enable_connect_protocol = settings
+                        .get(SettingId::ENABLE_CONNECT_PROTOCOL)
+                        .map(|v| v != 0)
+                        .unwrap_or(false);
+                    shared.peer_datagram_enabled = settings
+                        .get(SettingId::H3_DATAGRAM)
+                        .map(|v| v != 0)
+                        .unwrap_or(false);
+                    drop(shared);
                     Ok(Frame::Settings(settings))
                 }
-                Frame::CancelPush(_) | Frame::MaxPushId(_) | Frame::Goaway(_)
+                Frame::CancelPush(_)
+                | Frame::MaxPushId(_)
+                | Frame::Goaway(_)
+                | Frame::PriorityUpdateRequest { .. }
+                | Frame::PriorityUpdatePush { .. }
                     if !self.got_peer_settings =>
                 {
                     Err(Code::H3_MISSING_SETTINGS.into())
                 }
+                Frame::Goaway(id) => {
+                    self.shared.write("connection goaway write").goaway_received = Some(id);
+                    Ok(Frame::Goaway(id))
+                }
+                Frame::CancelPush(push_id) => {
+                    let mut shared = self.shared.write("connection cancel push write");
+                    shared.cancelled_pushes.insert(push_id);
+                    drop(shared);
+                    self.pending_push_streams.retain(|(id, _)| *id != push_id);
+                    Ok(Frame::CancelPush(push_id))
+                }
+                // only a server receives MAX_PUSH_ID; this client-only module has nothing to
+                // enforce with it, so just hand the frame back for the caller to reject
+                Frame::MaxPushId(max_push_id) => Ok(Frame::MaxPushId(max_push_id)),
+                Frame::PriorityUpdateRequest {
+                    element_id,
+                    priority_field_value,
+                } => {
+                    let priority = Priority::parse(std::str::from_utf8(&priority_field_value)
+                        .map_err(|_| Code::H3_GENERAL_PROTOCOL_ERROR.with_reason("invalid PRIORITY_UPDATE value"))?)?;
+                    self.shared
+                        .write("connection priority write")
+                        .priorities
+                        .insert(element_id, priority);
+                    Ok(Frame::PriorityUpdateRequest {
+                        element_id,
+                        priority_field_value,
+                    })
+                }
+                Frame::PriorityUpdatePush {
+                    element_id,
+                    priority_field_value,
+                } => {
+                    let priority = Priority::parse(std::str::from_utf8(&priority_field_value)
+                        .map_err(|_| Code::H3_GENERAL_PROTOCOL_ERROR.with_reason("invalid PRIORITY_UPDATE value"))?)?;
+                    self.shared
+                        .write("connection priority write")
+                        .priorities
+                        .insert(element_id, priority);
+                    Ok(Frame::PriorityUpdatePush {
+                        element_id,
+                        priority_field_value,
+                    })
+                }
                 frame => Err(Code::H3_FRAME_UNEXPECTED
                     .with_reason(format!("on control stream: {:?}", frame))),
             },
@@ -189,6 +578,73 @@ where
         Poll::Ready(res)
     }
 
+    /// Send a GOAWAY frame carrying `id` (interpreted as a stream id or a push
+    /// id depending on the caller's role, per
+    /// [RFC9114 section 5.2](https://www.rfc-editor.org/rfc/rfc9114.html#section-5.2)),
+    /// then wait for every `RequestStream` that registered a close notifier
+    /// (see `RequestStream::register_close_notifier`) and is live at the time
+    /// of the call to be dropped before returning. Streams that never
+    /// registered one, such as accepted push streams, are not waited for.
+    pub async fn shutdown(&mut self, id: VarInt) -> Result<(), Error> {
+        stream::write(&mut self.control_send, Frame::Goaway(id)).await?;
+
+        let mut pending = Vec::new();
+        while let Ok(Some(recv)) = self.request_close_rx.try_next() {
+            pending.push(recv);
+        }
+        for recv in pending {
+            let _ = recv.await;
+        }
+
+        Ok(())
+    }
+
+    /// Send a `PRIORITY_UPDATE` frame reprioritizing the request stream
+    /// identified by `stream_id`.
+    pub async fn send_priority_update(
+        &mut self,
+        stream_id: VarInt,
+        priority: Priority,
+    ) -> Result<(), Error> {
+        stream::write(
+            &mut self.control_send,
+            Frame::PriorityUpdateRequest {
+                element_id: stream_id,
+                priority_field_value: Bytes::from(priority.serialize()),
+            },
+        )
+        .await
+    }
+
+    /// Send a `PRIORITY_UPDATE` frame reprioritizing the push identified by
+    /// `push_id`.
+    pub async fn send_push_priority_update(
+        &mut self,
+        push_id: VarInt,
+        priority: Priority,
+    ) -> Result<(), Error> {
+        stream::write(
+            &mut self.control_send,
+            Frame::PriorityUpdatePush {
+                element_id: push_id,
+                priority_field_value: Bytes::from(priority.serialize()),
+            },
+        )
+        .await
+    }
+
+    /// The priority most recently signalled for `id` (a request or push id)
+    /// via a received `PRIORITY_UPDATE` frame, or [`Priority::default`] if
+    /// none has been received.
+    pub fn priority_for(&self, id: VarInt) -> Priority {
+        self.shared
+            .read("priority_for")
+            .priorities
+            .get(&id)
+            .copied()
+            .unwrap_or_default()
+    }
+
     pub fn close(&mut self, code: Code, reason: &str) -> Error {
         self.shared.0.write().expect("connection close err").error = Some(code.with_reason(reason));
         self.conn.close(code, reason.as_bytes());
@@ -201,6 +657,16 @@ pub struct RequestStream<S, B> {
     pub(super) trailers: Option<Bytes>,
     pub(super) conn_state: SharedStateRef,
     pub(super) max_field_section_size: u64,
+    // HTTP/3 datagrams received for this stream's flow id, if it has one
+    datagrams_rx: Option<mpsc::UnboundedReceiver<Bytes>>,
+    // the quarter stream id this stream registered in `SharedState::datagram_senders`, so
+    // the entry can be removed once this stream is dropped instead of leaking for the life
+    // of the connection
+    datagram_flow_id: Option<VarInt>,
+    // fired on drop so a `shutdown` waiting on `SharedState::request_close_tx` learns this
+    // request has finished; `None` unless `register_close_notifier` was called while the
+    // connection tracks close notifiers
+    close_tx: Option<oneshot::Sender<()>>,
     _phantom_buffer: PhantomData<B>,
 }
 
@@ -211,9 +677,85 @@ impl<S, B> RequestStream<S, B> {
             conn_state,
             max_field_section_size,
             trailers: None,
+            datagrams_rx: None,
+            datagram_flow_id: None,
+            close_tx: None,
             _phantom_buffer: PhantomData,
         }
     }
+
+    /// Register this stream with the connection's graceful `shutdown`, so
+    /// that a pending `shutdown` call waits for this stream to be dropped
+    /// before closing the connection.
+    pub fn register_close_notifier(mut self) -> Self {
+        let (tx, rx) = oneshot::channel();
+        let registered = self
+            .conn_state
+            .read("register_close_notifier")
+            .request_close_tx
+            .clone();
+        if let Some(sender) = registered {
+            if sender.unbounded_send(rx).is_ok() {
+                self.close_tx = Some(tx);
+            }
+        }
+        self
+    }
+
+    /// Register this stream's quarter stream id as an HTTP/3 datagram flow
+    /// identifier, so that datagrams demultiplexed by the connection driver
+    /// reach [`poll_recv_datagram`](Self::poll_recv_datagram). The
+    /// registration is removed again once this stream is dropped.
+    ///
+    /// Callers must only do this once datagrams are actually enabled locally
+    /// (`SharedState::datagram_outbound.is_some()`); registering
+    /// unconditionally would leak an entry in `SharedState::datagram_senders`
+    /// for every request on connections that never negotiated datagrams.
+    pub fn with_datagrams(mut self, stream_id: VarInt) -> Self {
+        let (tx, rx) = mpsc::unbounded();
+        let quarter_id = quarter_stream_id(stream_id);
+        self.conn_state
+            .write("register datagram flow")
+            .datagram_senders
+            .insert(quarter_id, tx);
+        self.datagrams_rx = Some(rx);
+        self.datagram_flow_id = Some(quarter_id);
+        self
+    }
+
+    /// Send an HTTP/3 datagram associated with this stream, per
+    /// [RFC9297](https://www.rfc-editor.org/rfc/rfc9297.html). Errors with
+    /// `H3_SETTINGS_ERROR` unless the peer advertised `SETTINGS_H3_DATAGRAM`,
+    /// and with `H3_INTERNAL_ERROR` if this stream registered no datagram flow
+    /// (see [`with_datagrams`](Self::with_datagrams)), as for a pushed response.
+    pub fn send_datagram(&mut self, stream_id: VarInt, data: Bytes) -> Result<(), Error> {
+        let state = self.conn_state.read("send_datagram");
+        if !state.peer_datagram_enabled {
+            return Err(Code::H3_SETTINGS_ERROR
+                .with_reason("peer did not advertise SETTINGS_H3_DATAGRAM"));
+        }
+        let outbound = state
+            .datagram_outbound
+            .clone()
+            .ok_or_else(|| Code::H3_SETTINGS_ERROR.with_reason("datagrams not enabled locally"))?;
+        drop(state);
+        if self.datagram_flow_id.is_none() {
+            return Err(Code::H3_INTERNAL_ERROR.with_reason("stream has no datagram flow"));
+        }
+
+        let quarter_id = quarter_stream_id(stream_id);
+        outbound
+            .unbounded_send((quarter_id, data))
+            .map_err(|e| Error::transport(e.into_send_error()))
+    }
+
+    /// Poll for the next HTTP/3 datagram addressed to this stream.
+    pub fn poll_recv_datagram(&mut self, cx: &mut Context<'_>) -> Poll<Option<Bytes>> {
+        match self.datagrams_rx.as_mut() {
+            Some(rx) => rx.poll_next_unpin(cx),
+            None => Poll::Ready(None),
+        }
+    }
 }
 
 impl<S, B> ConnectionState for RequestStream<S, B> {
@@ -222,6 +764,20 @@ impl<S, B> ConnectionState for RequestStream<S, B> {
     }
 }
 
+impl<S, B> Drop for RequestStream<S, B> {
+    fn drop(&mut self) {
+        if let Some(flow_id) = self.datagram_flow_id.take() {
+            self.conn_state
+                .write("drop datagram flow")
+                .datagram_senders
+                .remove(&flow_id);
+        }
+        if let Some(tx) = self.close_tx.take() {
+            let _ = tx.send(());
+        }
+    }
+}
+
 impl<S> RequestStream<FrameStream<S>, Bytes>
 where
     S: quic::RecvStream,
@@ -269,7 +825,17 @@ where
             return Err(Error::header_too_big(mem_size, self.max_field_section_size));
         }
 
-        Ok(Some(Header::try_from(fields)?.into_fields()))
+        let mut fields = Header::try_from(fields)?.into_fields();
+        let lenient = self
+            .conn_state
+            .read("recv_trailers shared state read")
+            .lenient_connection_headers;
+        if let Err(e) = sanitize_connection_headers(&mut fields, lenient) {
+            self.stop_sending(Code::H3_MESSAGE_ERROR);
+            return Err(e);
+        }
+
+        Ok(Some(fields))
     }
 
     pub fn stop_sending(&mut self, err_code: Code) {
@@ -300,8 +866,47 @@ where
         Ok(())
     }
 
+    /// Send a `PUSH_PROMISE` frame on this request stream, promising the
+    /// push identified by `push_id` for the given `method`, `uri` and
+    /// `headers`. Pair with [`ConnectionInner::open_push_stream`] to send
+    /// the promised response.
+    pub async fn send_push_promise(
+        &mut self,
+        push_id: VarInt,
+        method: http::Method,
+        uri: http::Uri,
+        headers: HeaderMap,
+    ) -> Result<(), Error> {
+        let promised = Header::request(method, uri, headers)?;
+
+        let mut block = BytesMut::new();
+        let mem_size = qpack::encode_stateless(&mut block, promised)?;
+        let max_mem_size = self
+            .conn_state
+            .read("send_push_promise shared state read")
+            .peer_max_field_section_size;
+        if mem_size > max_mem_size {
+            return Err(Error::header_too_big(mem_size, max_mem_size));
+        }
+
+        stream::write(
+            &mut self.stream,
+            Frame::PushPromise {
+                push_id,
+                header_block: block.freeze(),
+            },
+        )
+        .await
+    }
+
     /// Send a set of trailers to end the request.
-    pub async fn send_trailers(&mut self, trailers: HeaderMap) -> Result<(), Error> {
+    pub async fn send_trailers(&mut self, mut trailers: HeaderMap) -> Result<(), Error> {
+        let lenient = self
+            .conn_state
+            .read("send_trailers shared state read")
+            .lenient_connection_headers;
+        sanitize_connection_headers(&mut trailers, lenient)?;
+
         let mut block = BytesMut::new();
         let mem_size = qpack::encode_stateless(&mut block, Header::trailer(trailers))?;
         let max_mem_size = self
@@ -321,6 +926,22 @@ where
         Ok(())
     }
 
+    /// Same as [`send_trailers`](Self::send_trailers), but attaches a
+    /// `priority` header field carrying `priority`, as defined in
+    /// [RFC9218](https://www.rfc-editor.org/rfc/rfc9218.html).
+    pub async fn send_trailers_with_priority(
+        &mut self,
+        mut trailers: HeaderMap,
+        priority: Priority,
+    ) -> Result<(), Error> {
+        trailers.insert(
+            http::header::HeaderName::from_static("priority"),
+            http::header::HeaderValue::from_str(&priority.serialize())
+                .map_err(|e| Code::H3_INTERNAL_ERROR.with_cause(e))?,
+        );
+        self.send_trailers(trailers).await
+    }
+
     pub async fn finish(&mut self) -> Result<(), Error> {
         future::poll_fn(|cx| self.stream.poll_ready(cx))
             .await
@@ -330,3 +951,71 @@ where
             .map_err(|e| self.maybe_conn_err(Error::transport(e)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn priority_round_trips_through_serialize_and_parse() {
+        let priority = Priority::new(5, true).unwrap();
+        assert_eq!(Priority::parse(&priority.serialize()).unwrap(), priority);
+    }
+
+    #[test]
+    fn priority_defaults_on_empty_value() {
+        assert_eq!(Priority::parse("").unwrap(), Priority::default());
+    }
+
+    #[test]
+    fn priority_rejects_urgency_out_of_range() {
+        assert!(Priority::new(8, false).is_err());
+    }
+
+    #[test]
+    fn priority_rejects_malformed_incremental_value() {
+        assert!(Priority::parse("u=3, i=maybe").is_err());
+    }
+
+    #[test]
+    fn sanitize_connection_headers_strict_rejects_forbidden_fields() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            http::header::CONNECTION,
+            http::HeaderValue::from_static("close"),
+        );
+        assert!(sanitize_connection_headers(&mut headers, false).is_err());
+    }
+
+    #[test]
+    fn sanitize_connection_headers_lenient_strips_forbidden_fields() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            http::header::CONNECTION,
+            http::HeaderValue::from_static("close"),
+        );
+        sanitize_connection_headers(&mut headers, true).unwrap();
+        assert!(!headers.contains_key(http::header::CONNECTION));
+    }
+
+    #[test]
+    fn sanitize_connection_headers_allows_single_trailers_te() {
+        let mut headers = HeaderMap::new();
+        headers.insert(http::header::TE, http::HeaderValue::from_static("trailers"));
+        assert!(sanitize_connection_headers(&mut headers, false).is_ok());
+    }
+
+    #[test]
+    fn sanitize_connection_headers_rejects_a_repeated_te_with_a_non_trailers_value() {
+        let mut headers = HeaderMap::new();
+        headers.append(http::header::TE, http::HeaderValue::from_static("trailers"));
+        headers.append(http::header::TE, http::HeaderValue::from_static("gzip"));
+        assert!(sanitize_connection_headers(&mut headers, false).is_err());
+    }
+
+    #[test]
+    fn quarter_stream_id_divides_by_four_without_truncating_large_ids() {
+        let stream_id = VarInt::from_u64(VarInt::MAX.0 - 3).unwrap();
+        assert_eq!(quarter_stream_id(stream_id).0, (VarInt::MAX.0 - 3) / 4);
+    }
+}